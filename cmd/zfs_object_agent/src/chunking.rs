@@ -0,0 +1,311 @@
+use crate::object_access::ObjectAccess;
+use lazy_static::lazy_static;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Target/min/max chunk sizes.  These bound how much manifest metadata we
+// generate per object (smaller chunks => more dedup opportunities but more
+// manifest overhead).
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+const AVG_CHUNK_SIZE: usize = 256 * 1024;
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Mask chosen so that, for uniformly-random gear hash output, a boundary is
+// declared on average once every AVG_CHUNK_SIZE bytes (2^18 == 256KiB).
+const BOUNDARY_MASK: u64 = (AVG_CHUNK_SIZE as u64 - 1).next_power_of_two() - 1;
+
+// Fixed table of random 64-bit constants used by the gear hash, one per
+// byte value.  The actual values don't matter (they just need to be
+// reasonably well distributed); what matters is that they're stable so the
+// same input always chunks the same way, which is what makes dedup work.
+lazy_static! {
+    static ref GEAR: [u64; 256] = {
+        let mut table = [0u64; 256];
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        for (i, slot) in table.iter_mut().enumerate() {
+            // splitmix64
+            seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = seed ^ (i as u64);
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *slot = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// Split `data` into content-defined chunks using a gear/buzhash rolling
+/// hash, declaring a boundary whenever the rolling hash's low bits are all
+/// zero, clamped to `[MIN_CHUNK_SIZE, MAX_CHUNK_SIZE]` (except that the
+/// final chunk, holding whatever is left of the input, may be shorter than
+/// `MIN_CHUNK_SIZE`).  Returns the byte ranges of each chunk, in order.
+fn chunk_boundaries(data: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    if data.is_empty() {
+        return ranges;
+    }
+    let mut start = 0;
+    let mut h: u64 = 0;
+    let mut i = 0;
+    while i < data.len() {
+        h = (h << 1).wrapping_add(GEAR[data[i] as usize]);
+        let len = i - start + 1;
+        if (len >= MIN_CHUNK_SIZE && h & BOUNDARY_MASK == 0) || len == MAX_CHUNK_SIZE {
+            ranges.push(start..i + 1);
+            start = i + 1;
+            h = 0;
+        }
+        i += 1;
+    }
+    if start < data.len() {
+        ranges.push(start..data.len());
+    }
+    ranges
+}
+
+fn content_key(chunk: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(chunk);
+    format!("chunk/{:x}", hasher.finalize())
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ChunkRef {
+    key: String,
+    size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Manifest {
+    chunks: Vec<ChunkRef>,
+}
+
+// In-memory refcounts for chunk keys, so that freeing a logical object only
+// deletes a chunk once nothing else references it. Durable across restarts:
+// `persist_refcounts` writes the whole table out as a snapshot object after
+// each chunk a put references and after each object free, and `ObjectAccess::load_chunk_refcounts` reloads it at
+// startup, so an agent restart doesn't forget what's shared.
+lazy_static! {
+    static ref REFCOUNTS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    // Serializes `persist_refcounts`'s clone-then-PUT so that concurrent
+    // `put_object_chunked`/`free_object_chunked` calls can't have an older
+    // snapshot's PUT (e.g. delayed by `retry()`'s backoff) land after, and
+    // revert, a newer one; see `persist_refcounts`.
+    static ref PERSIST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+// Key of the persisted `REFCOUNTS` snapshot; see `persist_refcounts`.
+const REFCOUNTS_KEY: &str = "refcounts/snapshot";
+
+fn manifest_key(key: &str) -> String {
+    format!("manifest/{}", key)
+}
+
+/// Whether `key` is untracked (refcount zero), without incrementing it --
+/// used by `put_object_chunked` to decide whether it needs to check/write
+/// the backing chunk before bumping the refcount.
+fn refcount_is_new(refcounts: &mut HashMap<String, u64>, key: &str) -> bool {
+    *refcounts.entry(key.to_string()).or_insert(0) == 0
+}
+
+/// Increments `key`'s refcount, inserting it at 1 if untracked.
+fn refcount_acquire(refcounts: &mut HashMap<String, u64>, key: &str) {
+    *refcounts.entry(key.to_string()).or_insert(0) += 1;
+}
+
+/// Decrements `key`'s refcount, removing and returning `true` once it drops
+/// to zero (meaning the caller should delete the backing chunk). An
+/// untracked key is left alone and returns `false`, conservatively assuming
+/// it may still be referenced.
+fn refcount_release(refcounts: &mut HashMap<String, u64>, key: &str) -> bool {
+    match refcounts.get_mut(key) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            false
+        }
+        Some(_) => {
+            refcounts.remove(key);
+            true
+        }
+        None => false,
+    }
+}
+
+// XXX writes out the whole refcount table on every chunk put and every object free, which is
+// fine at the table sizes we expect today but doesn't scale indefinitely;
+// a production deployment would want an append-only delta log (like
+// `ZettaCacheIndex`'s `BlockBasedLog`) instead of a full rewrite per chunk.
+async fn persist_refcounts(object_access: &ObjectAccess) {
+    // Held across the PUT (not just the clone) so that the snapshot taken
+    // and the order PUTs land in S3 always agree -- see `PERSIST_LOCK`.
+    let _guard = PERSIST_LOCK.lock().await;
+    let snapshot = REFCOUNTS.lock().unwrap().clone();
+    let bytes = bincode::serialize(&snapshot).expect("refcount snapshot serialization can't fail");
+    object_access.put_object(REFCOUNTS_KEY, bytes).await;
+}
+
+impl ObjectAccess {
+    /// Loads the `REFCOUNTS` snapshot persisted by `persist_refcounts`, if
+    /// one exists, so that refcounts tracked before a restart aren't
+    /// forgotten. Must be called once at startup, before any
+    /// `put_object_chunked`/`free_object_chunked` calls; a fresh deployment
+    /// with no snapshot yet is a no-op.
+    pub async fn load_chunk_refcounts(&self) {
+        if !self.object_exists(REFCOUNTS_KEY).await {
+            return;
+        }
+        let bytes = self.get_object(REFCOUNTS_KEY).await;
+        let loaded: HashMap<String, u64> =
+            bincode::deserialize(&bytes).expect("corrupt chunk refcount snapshot");
+        *REFCOUNTS.lock().unwrap() = loaded;
+    }
+
+    /// Like `put_object`, but splits `data` into content-addressed chunks
+    /// and writes a small manifest object (under `key`) listing them, so
+    /// that identical chunks shared with other objects are only stored
+    /// once.
+    pub async fn put_object_chunked(&self, key: &str, data: Vec<u8>) {
+        let mut manifest = Manifest::default();
+        for range in chunk_boundaries(&data) {
+            let chunk = &data[range.clone()];
+            let chunk_key = content_key(chunk);
+
+            // need this block separate so that we can drop the mutex before the .await
+            let is_new = refcount_is_new(&mut REFCOUNTS.lock().unwrap(), &chunk_key);
+            if is_new && !self.object_exists(&chunk_key).await {
+                debug!("writing new chunk {} ({} bytes)", chunk_key, chunk.len());
+                self.put_object(&chunk_key, chunk.to_vec()).await;
+            }
+            refcount_acquire(&mut REFCOUNTS.lock().unwrap(), &chunk_key);
+            persist_refcounts(self).await;
+
+            manifest.chunks.push(ChunkRef {
+                key: chunk_key,
+                size: chunk.len(),
+            });
+        }
+
+        let manifest_bytes =
+            bincode::serialize(&manifest).expect("manifest serialization can't fail");
+        self.put_object(&manifest_key(key), manifest_bytes).await;
+    }
+
+    /// Like `get_object`, but for an object previously written with
+    /// `put_object_chunked`: fetches the manifest, then concatenates its
+    /// chunks (each of which goes through the usual object cache).
+    pub async fn get_object_chunked(&self, key: &str) -> Arc<Vec<u8>> {
+        let manifest_bytes = self.get_object(&manifest_key(key)).await;
+        let manifest: Manifest =
+            bincode::deserialize(&manifest_bytes).expect("corrupt chunk manifest");
+
+        let mut data = Vec::with_capacity(manifest.chunks.iter().map(|c| c.size).sum());
+        for chunk_ref in &manifest.chunks {
+            let chunk = self.get_object(&chunk_ref.key).await;
+            data.extend_from_slice(&chunk);
+        }
+        Arc::new(data)
+    }
+
+    /// Free a chunked object: deletes its manifest, and decrements each
+    /// referenced chunk's refcount, deleting the chunk once nothing else
+    /// references it.
+    pub async fn free_object_chunked(&self, key: &str) {
+        let manifest_bytes = self.get_object(&manifest_key(key)).await;
+        let manifest: Manifest =
+            bincode::deserialize(&manifest_bytes).expect("corrupt chunk manifest");
+
+        for chunk_ref in &manifest.chunks {
+            let should_delete = refcount_release(&mut REFCOUNTS.lock().unwrap(), &chunk_ref.key);
+            if should_delete {
+                debug!("deleting unreferenced chunk {}", chunk_ref.key);
+                self.delete_object(&chunk_ref.key).await;
+            }
+        }
+        persist_refcounts(self).await;
+        self.delete_object(&manifest_key(key)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_boundaries_of_empty_input_is_empty() {
+        assert!(chunk_boundaries(&[]).is_empty());
+    }
+
+    #[test]
+    fn chunk_boundaries_under_min_size_is_one_chunk() {
+        let data = vec![0u8; MIN_CHUNK_SIZE - 1];
+        assert_eq!(chunk_boundaries(&data), vec![0..data.len()]);
+    }
+
+    #[test]
+    fn chunk_boundaries_exactly_min_size_is_one_chunk() {
+        // A boundary can only be *considered* once `len >= MIN_CHUNK_SIZE`,
+        // which for data this short only happens at the very last byte, so
+        // this is always a single chunk covering the whole input regardless
+        // of what the gear hash says there.
+        let data = vec![0u8; MIN_CHUNK_SIZE];
+        assert_eq!(chunk_boundaries(&data), vec![0..MIN_CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn chunk_boundaries_exactly_max_size_is_one_chunk() {
+        // `len == MAX_CHUNK_SIZE` forces a boundary regardless of the hash,
+        // and for data this short that can only happen at the last byte.
+        let data = vec![0u8; MAX_CHUNK_SIZE];
+        assert_eq!(chunk_boundaries(&data), vec![0..MAX_CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn chunk_boundaries_respect_min_and_max_and_cover_input() {
+        // Varied byte values so the gear hash actually moves, rather than
+        // only exercising the degenerate constant-input case above.
+        let data: Vec<u8> = (0..MAX_CHUNK_SIZE * 3 + 12345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let ranges = chunk_boundaries(&data);
+        assert!(!ranges.is_empty());
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        for (i, range) in ranges.iter().enumerate() {
+            let len = range.end - range.start;
+            assert!(len <= MAX_CHUNK_SIZE, "chunk {} too large: {}", i, len);
+            // Only the last chunk may be shorter than MIN_CHUNK_SIZE -- it's
+            // whatever's left over at the end of the input.
+            if i + 1 < ranges.len() {
+                assert!(len >= MIN_CHUNK_SIZE, "chunk {} too small: {}", i, len);
+            }
+        }
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start, "ranges must be contiguous");
+        }
+    }
+
+    #[test]
+    fn refcount_acquire_and_release_are_balanced() {
+        let mut refcounts = HashMap::new();
+        assert!(refcount_is_new(&mut refcounts, "k"));
+        refcount_acquire(&mut refcounts, "k");
+        assert!(!refcount_is_new(&mut refcounts, "k"));
+        refcount_acquire(&mut refcounts, "k"); // second reference
+        assert_eq!(refcounts["k"], 2);
+
+        assert!(!refcount_release(&mut refcounts, "k")); // still referenced once
+        assert_eq!(refcounts["k"], 1);
+        assert!(refcount_release(&mut refcounts, "k")); // drops to zero
+        assert!(!refcounts.contains_key("k"));
+    }
+
+    #[test]
+    fn refcount_release_of_untracked_key_is_conservative() {
+        let mut refcounts = HashMap::new();
+        assert!(!refcount_release(&mut refcounts, "unknown"));
+    }
+}
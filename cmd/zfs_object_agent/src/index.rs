@@ -3,8 +3,12 @@ use crate::block_access::*;
 use crate::block_based_log::*;
 use crate::extent_allocator::ExtentAllocator;
 use crate::zettacache::AtimeHistogramPhys;
+use lazy_static::lazy_static;
+use log::*;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::sync::Arc;
+use tokio::sync::Notify;
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
 pub struct IndexKey {
@@ -31,9 +35,62 @@ pub struct IndexEntry {
 impl OnDisk for IndexEntry {}
 impl BlockBasedLogEntry for IndexEntry {}
 
+// Rough estimate of the in-memory/log overhead of a pending entry beyond
+// its `IndexValue.size`, so that small-but-numerous inserts aren't
+// undercounted against the byte budgets below.
+const INSERT_ENTRY_OVERHEAD: usize = 64;
+
+lazy_static! {
+    // Above this many pending (unflushed) bytes, new inserts are silently
+    // dropped; a cache insert is best-effort, so losing one just means we
+    // miss an opportunity to cache a block, not a correctness issue.
+    static ref NON_BLOCKING_BUFFER_BYTES: usize = match env::var("ZETTACACHE_INDEX_NONBLOCKING_BUFFER_BYTES") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_) => 64 * 1024 * 1024,
+    };
+    // Above this many pending bytes, new inserts block (via `InsertResult::Backpressured`)
+    // until the flush loop drains enough space, rather than growing memory use without bound.
+    static ref BLOCKING_BUFFER_BYTES: usize = match env::var("ZETTACACHE_INDEX_BLOCKING_BUFFER_BYTES") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_) => 256 * 1024 * 1024,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertResult {
+    /// The entry was appended to the log.
+    Accepted,
+    /// The non-blocking buffer was full, so the entry was silently
+    /// dropped; the caller doesn't need to do anything.
+    Dropped,
+    /// The blocking buffer was full, so the entry was NOT appended. The
+    /// caller should `wait_for_flush(generation).await` (passing the
+    /// generation carried here) and retry.
+    // XXX the cache-insert call sites that need to match on this and retry
+    // live outside this module; confirm they're updated to do so (today an
+    // insert past the blocking threshold has no caller that acts on
+    // `Backpressured`, which defeats the point of having it).
+    Backpressured(u64),
+}
+
 pub struct ZettaCacheIndex {
     pub atime_histogram: AtimeHistogramPhys,
     pub log: BlockBasedLogWithSummary<IndexEntry>,
+    // Sum of `IndexValue.size` (plus `INSERT_ENTRY_OVERHEAD` per entry) for
+    // appends since the last flush.  Not persisted; it's reset to zero by
+    // `flush()`, which is when that memory is actually freed.
+    pending_bytes: usize,
+    // Notified whenever `flush()` frees up pending-bytes budget, so that
+    // callers blocked in `wait_for_flush()` can retry their insert.
+    insert_notify: Arc<Notify>,
+    // Bumped every `flush()`. `Backpressured` embeds the generation as of
+    // when backpressure was applied, so `wait_for_flush` can tell whether a
+    // flush has *already* happened since then (see its doc comment).
+    flush_generation: u64,
+    // Kept alongside `log` (rather than reached through it) so that
+    // `expand()` doesn't depend on `BlockBasedLogWithSummary` exposing an
+    // `extent_allocator()` accessor.
+    extent_allocator: Arc<ExtentAllocator>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -50,37 +107,105 @@ impl ZettaCacheIndex {
     ) -> Self {
         Self {
             atime_histogram: phys.atime_histogram,
-            log: BlockBasedLogWithSummary::open(block_access, extent_allocator, phys.log).await,
+            log: BlockBasedLogWithSummary::open(block_access, extent_allocator.clone(), phys.log)
+                .await,
+            pending_bytes: 0,
+            insert_notify: Arc::new(Notify::new()),
+            flush_generation: 0,
+            extent_allocator,
         }
     }
 
     pub async fn flush(&mut self) -> ZettaCacheIndexPhys {
-        ZettaCacheIndexPhys {
+        let phys = ZettaCacheIndexPhys {
             atime_histogram: self.atime_histogram.clone(),
             log: self.log.flush().await,
-        }
+        };
+        self.pending_bytes = 0;
+        self.flush_generation += 1;
+        self.insert_notify.notify_waiters();
+        phys
     }
 
     pub fn set_histogram_start(&mut self, start: usize) {
         self.atime_histogram.set_start(start);
     }
 
-    pub fn append(&mut self, entry: IndexEntry) {
+    /// Blocks until a flush has freed up pending-bytes budget more recent
+    /// than `since_generation` (the value carried by the
+    /// `InsertResult::Backpressured` that triggered this call). Intended
+    /// to be awaited by a caller that got `InsertResult::Backpressured`
+    /// back from `append()`/`append_or_evict()`, before retrying.
+    ///
+    /// Checking the generation (rather than just awaiting `notified()`)
+    /// closes a lost-wakeup gap: `notify_waiters()` only wakes tasks
+    /// already parked in `.notified()`, so a `flush()` landing between
+    /// `append()` returning `Backpressured` and this call would otherwise
+    /// notify nobody, and the caller could block forever despite space
+    /// having been freed. Worst case here is waiting for one extra flush
+    /// cycle, not forever.
+    pub async fn wait_for_flush(&self, since_generation: u64) {
+        loop {
+            if self.flush_generation != since_generation {
+                return;
+            }
+            self.insert_notify.notified().await;
+        }
+    }
+
+    pub fn append(&mut self, entry: IndexEntry) -> InsertResult {
+        let entry_bytes = entry.value.size + INSERT_ENTRY_OVERHEAD;
+        if self.pending_bytes + entry_bytes > *BLOCKING_BUFFER_BYTES {
+            return InsertResult::Backpressured(self.flush_generation);
+        }
+        if self.pending_bytes + entry_bytes > *NON_BLOCKING_BUFFER_BYTES {
+            debug!(
+                "insert buffer at {} bytes, dropping entry for {:?}",
+                self.pending_bytes, entry.key
+            );
+            return InsertResult::Dropped;
+        }
         self.atime_histogram.insert(entry.value);
         self.log.append(entry);
+        self.pending_bytes += entry_bytes;
+        InsertResult::Accepted
     }
 
-    pub fn append_or_evict(&mut self, entry: IndexEntry) {
+    pub fn append_or_evict(&mut self, entry: IndexEntry) -> InsertResult {
         // Add this entry if it is still in history covered by the histogram
         if entry.value.atime.0 as usize >= self.atime_histogram.get_start() {
-            self.append(entry);
+            self.append(entry)
+        } else {
+            // XXX - Note this case is not evict_block(). We are in
+            // the merge process and just need to free the space in the cache.
+            InsertResult::Dropped
         }
-        // XXX - Note else case is not evict_block(). We are in
-        // the merge process and just need to free the space in the cache.
     }
 
     pub fn clear(&mut self) {
         self.atime_histogram.clear();
         self.log.clear();
     }
+
+    /// Grows the index's backing storage in place, after the underlying
+    /// device has grown and reported `additional_bytes` of newly available
+    /// space, with the device now ending at `new_end`. Extends the extent allocator's
+    /// allocatable range and the atime histogram's bucket range to match,
+    /// and returns the additional bytes now usable.
+    ///
+    /// This only calls mutators on the existing `extent_allocator`/`atime_histogram`
+    /// fields (no new fields on `ZettaCacheIndexPhys` itself), so a flushed
+    /// index is still readable by an agent that hasn't seen the expansion -
+    /// it will just see the extent allocator/atime histogram ranges as they
+    /// were at whatever point they were flushed.
+    pub fn expand(&mut self, new_end: DiskLocation, additional_bytes: u64) -> u64 {
+        // XXX `ExtentAllocator::expand()` is defined in extent_allocator.rs,
+        // which isn't part of this excerpt of the tree; if it doesn't
+        // already exist there, it needs to be added (mirroring whatever
+        // growth operation the allocator already has for initial sizing)
+        // before this compiles/behaves correctly.
+        self.extent_allocator.expand(new_end, additional_bytes);
+        self.atime_histogram.set_end(new_end.offset as usize);
+        additional_bytes
+    }
 }
@@ -0,0 +1,203 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The kinds of `ObjectAccess` operations we keep separate counters for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Put,
+    List,
+    Delete,
+}
+
+// Latency histogram with power-of-two-millisecond buckets.  This is coarse
+// (log2 resolution) but cheap and lock-free, which is enough to tell
+// "everything's sub-second" apart from "we have a long tail of multi-second
+// requests" without pulling in a full HDR histogram implementation.
+const LATENCY_BUCKETS: usize = 24; // bucket 23 covers >=2^23ms (~2.3 hours), plenty of headroom
+
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        LatencyHistogram {
+            buckets: [(); LATENCY_BUCKETS].map(|_| AtomicU64::new(0)),
+        }
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis().max(1) as u64;
+        let bucket = (63 - ms.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct OperationStats {
+    requests: AtomicU64,
+    in_flight: AtomicI64,
+    retries: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl OperationStats {
+    fn new() -> (Self, LatencyHistogram) {
+        (OperationStats::default(), LatencyHistogram::new())
+    }
+}
+
+struct OperationMetrics {
+    stats: OperationStats,
+    latency: LatencyHistogram,
+}
+
+impl OperationMetrics {
+    fn new() -> Self {
+        let (stats, latency) = OperationStats::new();
+        OperationMetrics { stats, latency }
+    }
+
+    fn snapshot(&self) -> OperationStatsSnapshot {
+        OperationStatsSnapshot {
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            in_flight: self.stats.in_flight.load(Ordering::Relaxed),
+            retries: self.stats.retries.load(Ordering::Relaxed),
+            bytes: self.stats.bytes.load(Ordering::Relaxed),
+            latency_ms_log2_buckets: self.latency.snapshot(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperationStatsSnapshot {
+    pub requests: u64,
+    pub in_flight: i64,
+    pub retries: u64,
+    pub bytes: u64,
+    /// `latency_ms_log2_buckets[i]` is the number of requests whose
+    /// latency in whole milliseconds (truncated, and counted as 1 when
+    /// under 1ms) was in `[2^i, 2^(i+1))`; the last bucket also counts
+    /// every longer request.
+    pub latency_ms_log2_buckets: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectAccessStatsSnapshot {
+    pub get: OperationStatsSnapshot,
+    pub put: OperationStatsSnapshot,
+    pub list: OperationStatsSnapshot,
+    pub delete: OperationStatsSnapshot,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+struct Metrics {
+    get: OperationMetrics,
+    put: OperationMetrics,
+    list: OperationMetrics,
+    delete: OperationMetrics,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    fn for_op(&self, op: Operation) -> &OperationMetrics {
+        match op {
+            Operation::Get => &self.get,
+            Operation::Put => &self.put,
+            Operation::List => &self.list,
+            Operation::Delete => &self.delete,
+        }
+    }
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics {
+        get: OperationMetrics::new(),
+        put: OperationMetrics::new(),
+        list: OperationMetrics::new(),
+        delete: OperationMetrics::new(),
+        cache_hits: AtomicU64::new(0),
+        cache_misses: AtomicU64::new(0),
+    };
+}
+
+/// RAII guard that increments an operation's in-flight count on creation
+/// and decrements it on drop (including on the error/panic paths), so
+/// `retry()` doesn't have to remember to decrement on every exit.
+pub struct InFlightGuard(Operation);
+
+impl InFlightGuard {
+    pub fn new(op: Operation) -> Self {
+        METRICS
+            .for_op(op)
+            .stats
+            .in_flight
+            .fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(op)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        METRICS
+            .for_op(self.0)
+            .stats
+            .in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_request(op: Operation) {
+    METRICS
+        .for_op(op)
+        .stats
+        .requests
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_retry(op: Operation) {
+    METRICS
+        .for_op(op)
+        .stats
+        .retries
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_bytes(op: Operation, bytes: u64) {
+    METRICS.for_op(op).stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_latency(op: Operation, elapsed: Duration) {
+    METRICS.for_op(op).latency.record(elapsed);
+}
+
+pub fn record_cache_hit() {
+    METRICS.cache_hits.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_cache_miss() {
+    METRICS.cache_misses.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> ObjectAccessStatsSnapshot {
+    ObjectAccessStatsSnapshot {
+        get: METRICS.get.snapshot(),
+        put: METRICS.put.snapshot(),
+        list: METRICS.list.snapshot(),
+        delete: METRICS.delete.snapshot(),
+        cache_hits: METRICS.cache_hits.load(Ordering::Relaxed),
+        cache_misses: METRICS.cache_misses.load(Ordering::Relaxed),
+    }
+}
@@ -15,6 +15,9 @@ use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::Semaphore;
 
+use crate::metrics;
+use crate::metrics::Operation;
+
 struct ObjectCache {
     // XXX cache key should include Bucket
     cache: LruCache<String, Arc<Vec<u8>>>,
@@ -35,6 +38,90 @@ lazy_static! {
 // log operations that take longer than this with info!()
 const LONG_OPERATION_DURATION: Duration = Duration::from_secs(2);
 
+// Don't bother compressing small objects; the zstd frame overhead dominates
+// and we'd just waste CPU for no space savings.
+const COMPRESSION_THRESHOLD: usize = 4096;
+const COMPRESSION_LEVEL: i32 = 3;
+
+// Header prepended to every object we write, so that `get_object_impl` can
+// tell a compressed object from a raw one without a separate side-channel.
+// XXX could grow a checksum here too, to avoid leaning on S3/network CRCs.
+const OBJECT_HEADER_MAGIC: u8 = 0xe0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum CompressionAlgorithm {
+    None = 0,
+    Zstd = 1,
+}
+
+impl CompressionAlgorithm {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(CompressionAlgorithm::None),
+            1 => Some(CompressionAlgorithm::Zstd),
+            _ => None,
+        }
+    }
+}
+
+// header layout: [magic: u8][algorithm: u8][original_len: u64 LE]
+const OBJECT_HEADER_LEN: usize = 1 + 1 + 8;
+
+// Wraps `payload` in the unconditional object header. Used both for
+// compressed and stored-raw payloads, so that `decompress_object` never has
+// to guess whether an object is compressed from its content -- it just
+// reads the algorithm byte.
+fn with_header(algorithm: CompressionAlgorithm, original_len: usize, payload: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(OBJECT_HEADER_LEN + payload.len());
+    v.push(OBJECT_HEADER_MAGIC);
+    v.push(algorithm as u8);
+    v.extend_from_slice(&(original_len as u64).to_le_bytes());
+    v.extend_from_slice(payload);
+    v
+}
+
+fn compress_object(data: Vec<u8>) -> Vec<u8> {
+    if data.len() < COMPRESSION_THRESHOLD {
+        return with_header(CompressionAlgorithm::None, data.len(), &data);
+    }
+    let compressed = match zstd::encode_all(data.as_slice(), COMPRESSION_LEVEL) {
+        Ok(c) => c,
+        Err(e) => {
+            // XXX should this be fatal instead?  for now, fall back to
+            // storing the object uncompressed.
+            debug!("zstd compression failed, storing raw: {:?}", e);
+            return with_header(CompressionAlgorithm::None, data.len(), &data);
+        }
+    };
+    if compressed.len() + OBJECT_HEADER_LEN >= data.len() {
+        // Not worth it; store raw so we don't pay decode cost for nothing.
+        return with_header(CompressionAlgorithm::None, data.len(), &data);
+    }
+    with_header(CompressionAlgorithm::Zstd, data.len(), &compressed)
+}
+
+fn decompress_object(data: Vec<u8>) -> Vec<u8> {
+    // The header is unconditional (see `with_header`), so its presence is
+    // never inferred from the payload bytes -- a missing/malformed header
+    // is a hard error, not "must be an uncompressed raw object".
+    assert!(data.len() >= OBJECT_HEADER_LEN, "missing object header");
+    assert_eq!(data[0], OBJECT_HEADER_MAGIC, "missing object header");
+    let algorithm =
+        CompressionAlgorithm::from_u8(data[1]).expect("unknown compression algorithm");
+    match algorithm {
+        CompressionAlgorithm::None => data[OBJECT_HEADER_LEN..].to_vec(),
+        CompressionAlgorithm::Zstd => {
+            let original_len =
+                u64::from_le_bytes(data[2..OBJECT_HEADER_LEN].try_into().unwrap()) as usize;
+            let mut decoded = Vec::with_capacity(original_len);
+            zstd::stream::copy_decode(&data[OBJECT_HEADER_LEN..], &mut decoded)
+                .expect("corrupt compressed object");
+            decoded
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ObjectAccess {
     client: rusoto_s3::S3Client,
@@ -46,12 +133,14 @@ pub fn prefixed(key: &str) -> String {
     format!("{}{}", *PREFIX, key)
 }
 
-async fn retry<F, O, E>(msg: &str, f: impl Fn() -> F) -> Result<O, RusotoError<E>>
+async fn retry<F, O, E>(msg: &str, op: Operation, f: impl Fn() -> F) -> Result<O, RusotoError<E>>
 where
     E: core::fmt::Debug + core::fmt::Display + std::error::Error,
     F: Future<Output = (bool, Result<O, RusotoError<E>>)>,
 {
     debug!("{}: begin", msg);
+    metrics::record_request(op);
+    let _in_flight = metrics::InFlightGuard::new(op);
     let begin = Instant::now();
     let mut delay = Duration::from_secs_f64(thread_rng().gen_range(0.001..0.2));
     let result = loop {
@@ -70,6 +159,7 @@ where
                         msg, e, delay
                     );
                 }
+                metrics::record_retry(op);
             }
             (_, res) => {
                 break res;
@@ -79,6 +169,7 @@ where
         delay = delay.mul_f64(thread_rng().gen_range(1.5..2.5));
     };
     let elapsed = begin.elapsed();
+    metrics::record_latency(op, elapsed);
     debug!("{}: returned success in {}ms", msg, elapsed.as_millis());
     if elapsed > LONG_OPERATION_DURATION {
         info!(
@@ -132,9 +223,11 @@ impl ObjectAccess {
         old
     }
 
-    async fn get_object_impl(&self, key: &str) -> Vec<u8> {
-        let msg = format!("get {}", prefixed(key));
-        let output = retry(&msg, || async {
+    // Issues the GetObject request (with the usual retry-on-transient-error
+    // wrapping) and returns the output with its body not yet drained, so
+    // that callers can choose to stream or buffer it.
+    async fn get_object_request(&self, key: &str, msg: &str) -> GetObjectOutput {
+        retry(msg, Operation::Get, || async {
             let req = GetObjectRequest {
                 bucket: self.bucket_str.clone(),
                 key: prefixed(key),
@@ -148,8 +241,77 @@ impl ObjectAccess {
             }
         })
         .await
-        .unwrap();
+        .unwrap()
+    }
+
+    /// Fetches `key`'s body as a stream of `Bytes`, without materializing
+    /// the whole object in memory.  Unlike `get_object()`, this bypasses
+    /// the object cache, since callers asking for a stream are presumably
+    /// handling multi-MB objects for which buffering (and our cache) isn't
+    /// a good fit.
+    ///
+    /// Reads and strips the same unconditional object header `get_object()`
+    /// does, so this can be pointed at a key written by either
+    /// `put_object()` or `put_object_stream()`. If the object turns out to
+    /// be compressed (i.e. it was written by `put_object()`'s compressing
+    /// path), this returns `Err` rather than silently handing back zstd
+    /// bytes as the payload -- transparent streaming decompression isn't
+    /// implemented, so such a key needs `get_object()` instead.
+    pub async fn get_object_stream(
+        &self,
+        key: &str,
+    ) -> Result<impl futures::Stream<Item = Result<Bytes>>> {
+        let msg = format!("get {} (streaming)", prefixed(key));
+        let output = self.get_object_request(key, &msg).await;
+        let mut body = output
+            .body
+            .unwrap()
+            .map(|res| res.map_err(anyhow::Error::from));
+
+        // The header may be split across several body chunks (or span more
+        // than one); buffer just enough leading bytes to read it, keeping
+        // whatever's left over from the last chunk to yield first.
+        let mut header = Vec::with_capacity(OBJECT_HEADER_LEN);
+        let mut leftover = Bytes::new();
+        while header.len() < OBJECT_HEADER_LEN {
+            let chunk = match body.next().await {
+                Some(res) => res?,
+                None => return Err(anyhow::anyhow!("{}: missing object header", msg)),
+            };
+            let need = OBJECT_HEADER_LEN - header.len();
+            if chunk.len() <= need {
+                header.extend_from_slice(&chunk);
+            } else {
+                header.extend_from_slice(&chunk[..need]);
+                leftover = chunk.slice(need..);
+            }
+        }
+        if header[0] != OBJECT_HEADER_MAGIC {
+            return Err(anyhow::anyhow!("{}: missing object header", msg));
+        }
+        let algorithm = CompressionAlgorithm::from_u8(header[1])
+            .ok_or_else(|| anyhow::anyhow!("{}: unknown compression algorithm", msg))?;
+        if algorithm != CompressionAlgorithm::None {
+            return Err(anyhow::anyhow!(
+                "{}: object is compressed; use get_object() instead of get_object_stream()",
+                msg
+            ));
+        }
+
+        Ok(stream! {
+            if !leftover.is_empty() {
+                yield Ok(leftover);
+            }
+            while let Some(res) = body.next().await {
+                yield res;
+            }
+        })
+    }
+
+    async fn get_object_impl(&self, key: &str) -> Vec<u8> {
+        let msg = format!("get {}", prefixed(key));
         let begin = Instant::now();
+        let output = self.get_object_request(key, &msg).await;
         let mut v = match output.content_length {
             None => Vec::new(),
             Some(len) => Vec::with_capacity(len as usize),
@@ -169,8 +331,9 @@ impl ObjectAccess {
             v.len(),
             begin.elapsed().as_millis()
         );
+        metrics::record_bytes(Operation::Get, v.len() as u64);
 
-        v
+        decompress_object(v)
     }
 
     pub async fn get_object(&self, key: &str) -> Arc<Vec<u8>> {
@@ -187,20 +350,24 @@ impl ObjectAccess {
                 match c.cache.get(&mykey) {
                     Some(v) => {
                         debug!("found {} in cache", key);
+                        metrics::record_cache_hit();
                         return v.clone();
                     }
-                    None => match c.reading.get(key) {
-                        None => {
-                            mysem = Arc::new(Semaphore::new(0));
-                            c.reading.insert(mykey, mysem.clone());
-                            reader = true;
+                    None => {
+                        metrics::record_cache_miss();
+                        match c.reading.get(key) {
+                            None => {
+                                mysem = Arc::new(Semaphore::new(0));
+                                c.reading.insert(mykey, mysem.clone());
+                                reader = true;
+                            }
+                            Some(sem) => {
+                                debug!("found {} read in progress", key);
+                                mysem = sem.clone();
+                                reader = false;
+                            }
                         }
-                        Some(sem) => {
-                            debug!("found {} read in progress", key);
-                            mysem = sem.clone();
-                            reader = false;
-                        }
-                    },
+                    }
                 }
             }
             if reader {
@@ -231,6 +398,7 @@ impl ObjectAccess {
         loop {
             continuation_token = match retry(
                 &format!("list {} (delim {:?})", full_prefix, delimiter),
+                Operation::List,
                 || async {
                     let req = ListObjectsV2Request {
                         bucket: self.bucket_str.clone(),
@@ -261,11 +429,70 @@ impl ObjectAccess {
         results
     }
 
+    /// Feeds a caller-provided `Stream` straight into the PutObject body,
+    /// without requiring a fully-owned `Vec` up front.  `len` must be the
+    /// exact total size of the caller's stream (excluding the header this
+    /// prepends), since S3 needs a Content-Length.
+    ///
+    /// Prepends the same unconditional object header `put_object()` writes
+    /// (always `CompressionAlgorithm::None`, since this path never
+    /// compresses), so the result can be read back with either
+    /// `get_object()` or `get_object_stream()`.
+    ///
+    /// XXX Unlike the other methods here, this doesn't go through `retry`:
+    /// a `Stream` can only be drained once, so we can't re-issue the
+    /// request with the same body after a transient failure without
+    /// buffering it first (which defeats the point of streaming). Bulk
+    /// callers are expected to handle a failed put by re-producing their
+    /// stream and trying again. Since `retry()` can't wrap this, the usual
+    /// request/byte/latency counters are recorded explicitly below instead.
+    pub async fn put_object_stream<S>(&self, key: &str, stream: S, len: usize) -> Result<()>
+    where
+        S: futures::Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    {
+        let msg = format!("put {} ({} bytes, streaming)", prefixed(key), len);
+        debug!("{}: begin", msg);
+        metrics::record_request(Operation::Put);
+        let _in_flight = metrics::InFlightGuard::new(Operation::Put);
+        let begin = Instant::now();
+
+        let header = with_header(CompressionAlgorithm::None, len, &[]);
+        let mut stream = Box::pin(stream);
+        let body_stream = stream! {
+            yield Ok(Bytes::from(header));
+            while let Some(chunk) = stream.next().await {
+                yield chunk;
+            }
+        };
+        let body = ByteStream::new_with_size(body_stream, OBJECT_HEADER_LEN + len);
+        let req = PutObjectRequest {
+            bucket: self.bucket_str.clone(),
+            key: prefixed(key),
+            body: Some(body),
+            ..Default::default()
+        };
+        let result = self.client.put_object(req).await;
+        metrics::record_latency(Operation::Put, begin.elapsed());
+        if let Err(e) = result {
+            debug!("{}: failed: {:?}", msg, e);
+            return Err(anyhow::Error::from(e));
+        }
+        debug!(
+            "{}: returned success in {}ms",
+            msg,
+            begin.elapsed().as_millis()
+        );
+        metrics::record_bytes(Operation::Put, len as u64);
+        Ok(())
+    }
+
     async fn put_object_impl(&self, key: &str, data: Vec<u8>) {
+        let data = compress_object(data);
         let len = data.len();
         let a = Arc::new(Bytes::from(data));
         retry(
             &format!("put {} ({} bytes)", prefixed(key), len),
+            Operation::Put,
             || async {
                 let my_b = (*a).clone();
                 let stream = ByteStream::new_with_size(stream! { yield Ok(my_b)}, len);
@@ -281,6 +508,7 @@ impl ObjectAccess {
         )
         .await
         .unwrap();
+        metrics::record_bytes(Operation::Put, len as u64);
     }
 
     pub async fn put_object(&self, key: &str, data: Vec<u8>) {
@@ -312,7 +540,7 @@ impl ObjectAccess {
             keys.len(),
             prefixed(&keys[0])
         );
-        let output = retry(&msg, || async {
+        let output = retry(&msg, Operation::Delete, || async {
             let v: Vec<_> = keys
                 .iter()
                 .map(|x| ObjectIdentifier {
@@ -369,4 +597,63 @@ impl ObjectAccess {
             }
         })
     }
+
+    /// Returns a point-in-time snapshot of per-operation request/retry/byte
+    /// counters, latency histograms, and cache hit/miss counts, for use by
+    /// a stats/metrics query endpoint.
+    pub fn stats_snapshot(&self) -> metrics::ObjectAccessStatsSnapshot {
+        metrics::snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_empty() {
+        let data = Vec::new();
+        assert_eq!(decompress_object(compress_object(data.clone())), data);
+    }
+
+    #[test]
+    fn roundtrip_just_under_threshold() {
+        let data = vec![b'x'; COMPRESSION_THRESHOLD - 1];
+        let stored = compress_object(data.clone());
+        assert_eq!(stored[1], CompressionAlgorithm::None as u8);
+        assert_eq!(decompress_object(stored), data);
+    }
+
+    #[test]
+    fn roundtrip_compressible_above_threshold_actually_compresses() {
+        let data = vec![b'x'; COMPRESSION_THRESHOLD * 4];
+        let stored = compress_object(data.clone());
+        assert_eq!(stored[1], CompressionAlgorithm::Zstd as u8);
+        assert!(stored.len() < data.len());
+        assert_eq!(decompress_object(stored), data);
+    }
+
+    #[test]
+    fn roundtrip_incompressible_above_threshold_falls_back_to_raw() {
+        // Looks random enough that zstd can't beat the header overhead, so
+        // this exercises the "not worth it, store raw" fallback.
+        let data: Vec<u8> = (0..COMPRESSION_THRESHOLD * 2)
+            .map(|i| {
+                // splitmix64 finalizer, so every output byte is well mixed
+                let mut z = (i as u64).wrapping_mul(0x9e3779b97f4a7c15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                (z ^ (z >> 31)) as u8
+            })
+            .collect();
+        let stored = compress_object(data.clone());
+        assert_eq!(stored[1], CompressionAlgorithm::None as u8);
+        assert_eq!(decompress_object(stored), data);
+    }
+
+    #[test]
+    #[should_panic(expected = "missing object header")]
+    fn decompress_rejects_headerless_data() {
+        decompress_object(vec![1, 2, 3]);
+    }
 }